@@ -0,0 +1,905 @@
+#!/usr/bin/env python3
+# Run with: python3 -m unittest test_deftsilo
+
+import contextlib
+import hashlib
+import importlib.machinery
+import importlib.util
+import io
+import os
+import shlex
+import shutil
+import subprocess
+import sys
+import tempfile
+import unittest
+
+DEFTSILO = os.path.join(os.path.dirname(os.path.abspath(__file__)), 'deftsilo')
+
+# deftsilo has no .py suffix, so it is loaded by path; no bytecode is left behind
+sys.dont_write_bytecode = True
+_loader = importlib.machinery.SourceFileLoader('deftsilo', DEFTSILO)
+_spec = importlib.util.spec_from_loader('deftsilo', _loader)
+deftsilo = importlib.util.module_from_spec(_spec)
+_loader.exec_module(deftsilo)
+
+def sha256(data):
+    return hashlib.sha256(data).hexdigest()
+
+class Fixture(unittest.TestCase):
+    # a repository with two commits of .bashrc, and an empty target next to it
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory(prefix='deftsilo-test')
+        self.root = os.path.join(self.tmp.name, 'dotfiles')
+        self.target = os.path.join(self.tmp.name, 'home')
+        os.mkdir(self.root)
+        os.mkdir(self.target)
+        # the modes planned for directories are their modes here, whatever the umask
+        os.chmod(self.root, 0o755)
+        self.git('init', '-q')
+        self.write('.bashrc', b'one\n', 0o644)
+        self.write('bin/hello', b'#!/bin/sh\necho hello\n', 0o755)
+        self.commit('first')
+        self.write('.bashrc', b'two\n', 0o644)
+        self.commit('second')
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def git(self, *argv, env=None):
+        subprocess.run(('git', '-c', 'user.name=deftsilo', '-c', 'user.email=deftsilo@example.com',
+                        '-c', 'commit.gpgsign=false') + argv,
+                       cwd=self.root, check=True, stdout=subprocess.DEVNULL, env=env)
+
+    def write(self, path, data, mode):
+        path = os.path.join(self.root, path)
+        if not os.path.isdir(os.path.dirname(path)):
+            os.makedirs(os.path.dirname(path))
+            os.chmod(os.path.dirname(path), 0o755)
+        with open(path, 'wb') as fout:
+            fout.write(data)
+        os.chmod(path, mode)
+
+    def commit(self, message):
+        self.git('add', '-A')
+        self.git('commit', '-q', '-m', message)
+
+    def plan(self, *argv, directory=None):
+        # the same parsing and checks main does, stopping short of writing anything
+        parser = deftsilo.make_parser()
+        args = parser.parse_args(('--directory', directory or self.root + '/') + argv)
+        deftsilo.check_args(parser, args)
+        return deftsilo.plan(args.directory, args)
+
+    def generate(self, *argv, status=0):
+        # deftsilo run as a user would, leaving install.sh at the top of the repository
+        result = subprocess.run((sys.executable, DEFTSILO, '--directory', self.root + '/',
+                                 '--output', os.path.join(self.root, 'install.sh')) + argv,
+                                stdout=subprocess.PIPE, stderr=subprocess.PIPE, universal_newlines=True)
+        self.assertEqual(status, result.returncode, result.stderr)
+        return result
+
+    def install(self, *argv, status=0, env=None):
+        result = subprocess.run(('sh', os.path.join(self.root, 'install.sh')) + argv + (self.target,),
+                                stdout=subprocess.PIPE, stderr=subprocess.STDOUT, universal_newlines=True, env=env)
+        self.assertEqual(status, result.returncode, result.stdout)
+        return result.stdout
+
+    def fake_bin(self, name, body):
+        # a PATH with a shell script named name ahead of the real commands
+        bindir = tempfile.mkdtemp(dir=self.tmp.name)
+        with open(os.path.join(bindir, name), 'w') as fout:
+            fout.write('#!/bin/sh\n' + body)
+        os.chmod(os.path.join(bindir, name), 0o755)
+        return bindir + os.pathsep + os.environ['PATH']
+
+    def path_without(self, name):
+        # a PATH holding every command on the real one except name
+        bindir = tempfile.mkdtemp(dir=self.tmp.name)
+        for directory in os.environ['PATH'].split(os.pathsep):
+            if not os.path.isdir(directory):
+                continue
+            for command in os.listdir(directory):
+                if command != name and not os.path.lexists(os.path.join(bindir, command)):
+                    os.symlink(os.path.join(directory, command), os.path.join(bindir, command))
+        return bindir
+
+    def fake_uname(self, sysname):
+        return self.fake_bin('uname', 'echo {}\n'.format(sysname))
+
+    def script(self):
+        with open(os.path.join(self.root, 'install.sh')) as fin:
+            return fin.read().splitlines()
+
+    def installed(self, path):
+        with open(os.path.join(self.target, path), 'rb') as fin:
+            return fin.read()
+
+    def installs(self, actions):
+        return dict((a.path, a) for a in actions if isinstance(a, deftsilo.Install))
+
+class PlanTest(Fixture):
+
+    def test_directories_and_files(self):
+        actions = self.plan()
+        self.assertEqual([deftsilo.Mkdir('bin', '0755')], [a for a in actions if isinstance(a, deftsilo.Mkdir)])
+        installs = self.installs(actions)
+        self.assertEqual(['.bashrc', 'bin/hello'], sorted(installs))
+        self.assertEqual('0644', installs['.bashrc'].mode)
+        self.assertEqual('0755', installs['bin/hello'].mode)
+
+    def test_every_committed_version_is_accepted(self):
+        installs = self.installs(self.plan())
+        self.assertEqual(sorted([sha256(b'one\n'), sha256(b'two\n')]), installs['.bashrc'].hashes)
+
+    def test_uncommitted_version_is_accepted(self):
+        self.write('.bashrc', b'three\n', 0o644)
+        installs = self.installs(self.plan())
+        self.assertIn(sha256(b'three\n'), installs['.bashrc'].hashes)
+
+    def test_since_tag(self):
+        self.git('tag', 'v1', 'HEAD~1')
+        installs = self.installs(self.plan('--since-tag', 'v1'))
+        self.assertEqual([sha256(b'two\n')], installs['.bashrc'].hashes)
+
+    def test_unborn_head(self):
+        unborn = os.path.join(self.tmp.name, 'unborn')
+        os.mkdir(unborn)
+        subprocess.run(('git', 'init', '-q'), cwd=unborn, check=True)
+        with open(os.path.join(unborn, '.bashrc'), 'wb') as fout:
+            fout.write(b'new\n')
+        installs = self.installs(self.plan(directory=unborn + '/'))
+        self.assertEqual([sha256(b'new\n')], installs['.bashrc'].hashes)
+
+    def test_detached_head(self):
+        self.git('checkout', '-q', '--detach', 'HEAD~1')
+        installs = self.installs(self.plan())
+        self.assertEqual([sha256(b'one\n')], installs['.bashrc'].hashes)
+
+    def test_max_hashes(self):
+        # the newest committed versions, plus the one on disk
+        self.write('.bashrc', b'three\n', 0o644)
+        self.commit('third')
+        self.write('.bashrc', b'four\n', 0o644)
+        installs = self.installs(self.plan('--max-hashes', '1'))
+        self.assertEqual(sorted([sha256(b'three\n'), sha256(b'four\n')]), sorted(installs['.bashrc'].hashes))
+        self.assertEqual(4, len(self.installs(self.plan())['.bashrc'].hashes))
+
+    def test_trusted_author(self):
+        self.write('.bashrc', b'rogue\n', 0o644)
+        self.git('add', '-A')
+        self.git('-c', 'user.email=rogue@example.com', 'commit', '-q', '-m', 'rogue')
+        self.write('.bashrc', b'four\n', 0o644)
+        installs = self.installs(self.plan('--trusted-author', 'Deftsilo@example.com'))
+        self.assertEqual(sorted([sha256(b'one\n'), sha256(b'two\n'), sha256(b'four\n')]),
+                         sorted(installs['.bashrc'].hashes))
+        self.assertIn(sha256(b'rogue\n'), self.installs(self.plan())['.bashrc'].hashes)
+
+    def test_since_missing_tag(self):
+        with self.assertRaisesRegex(deftsilo.GitError, 'no tag nope in'):
+            self.plan('--since-tag', 'nope')
+
+    def test_only(self):
+        self.assertEqual(['.bashrc'], sorted(self.installs(self.plan('--only', '.bashrc'))))
+
+    def test_directory_without_slash_nests(self):
+        actions = self.plan('--only', '.bashrc', directory=self.root)
+        self.assertIn(deftsilo.Mkdir('dotfiles', '0755'), actions)
+        installs = self.installs(actions)
+        self.assertEqual(['dotfiles/.bashrc'], sorted(installs))
+        self.assertEqual('.bashrc', installs['dotfiles/.bashrc'].source)
+
+    def test_notes(self):
+        self.write('.deftsilo.notes', b'.bashrc  shell setup\n', 0o644)
+        self.commit('notes')
+        installs = self.installs(self.plan())
+        self.assertEqual('shell setup', installs['.bashrc'].note)
+        self.assertIsNone(installs['bin/hello'].note)
+        self.assertNotIn('.deftsilo.notes', installs)
+
+    def test_only_tracked(self):
+        self.write('scratch', b'untracked\n', 0o644)
+        self.write('build/out', b'untracked\n', 0o644)
+        self.write('etc/tracked', b'tracked\n', 0o644)
+        self.git('add', 'etc/tracked')
+        self.git('commit', '-q', '-m', 'tracked')
+        actions = self.plan('--only-tracked')
+        self.assertEqual(['.bashrc', 'bin/hello', 'etc/tracked'], sorted(self.installs(actions)))
+        self.assertEqual(['bin', 'etc'], sorted(a.path for a in actions if isinstance(a, deftsilo.Mkdir)))
+        self.assertIn('scratch', self.installs(self.plan()))
+
+    def test_os_suffix(self):
+        self.write('foo.linux', b'linux\n', 0o644)
+        self.write('foo.darwin', b'darwin\n', 0o644)
+        self.write('bar.plan9', b'neither\n', 0o644)
+        self.commit('variants')
+        systems = sorted((a.path, a.os, a.source) for a in self.plan('--os-suffix') if isinstance(a, deftsilo.Install))
+        self.assertIn(('foo', 'darwin', 'foo.darwin'), systems)
+        self.assertIn(('foo', 'linux', 'foo.linux'), systems)
+        self.assertIn(('bar.plan9', None, None), systems)
+        self.assertIn(('.bashrc', None, None), systems)
+
+    def test_collision(self):
+        self.write('foo.linux', b'linux\n', 0o644)
+        self.write('foo', b'base\n', 0o644)
+        self.commit('collide')
+        with self.assertRaisesRegex(deftsilo.DeftsiloError, 'foo, foo.linux all install to foo'):
+            self.plan('--os-suffix')
+
+    def test_tagged_hashes(self):
+        installs = self.installs(self.plan('--hash', 'sha256', '--hash', 'blake2b'))
+        self.assertIn('sha256:' + sha256(b'one\n'), installs['.bashrc'].hashes)
+        self.assertIn('blake2b:' + hashlib.blake2b(b'one\n').hexdigest(), installs['.bashrc'].hashes)
+        self.assertTrue(all(':' in h for h in installs['.bashrc'].hashes))
+
+    def test_separate_git_dir(self):
+        gitdir = os.path.join(self.tmp.name, 'dotfiles.git')
+        os.rename(os.path.join(self.root, '.git'), gitdir)
+        self.assertEqual([sha256(b'two\n')], self.installs(self.plan())['.bashrc'].hashes)
+        installs = self.installs(self.plan('--git-dir', gitdir, '--work-tree', self.root))
+        self.assertEqual(sorted([sha256(b'one\n'), sha256(b'two\n')]), installs['.bashrc'].hashes)
+
+    def test_nested_output_is_skipped(self):
+        self.write('scripts/install.sh', b'#!/bin/sh\n', 0o755)
+        self.commit('scripts')
+        output = os.path.join(self.root, 'scripts/install.sh')
+        self.assertNotIn('scripts/install.sh', self.installs(self.plan('--output', output)))
+        self.assertIn('scripts/install.sh', self.installs(self.plan('--output', os.path.join(self.root, 'install.sh'))))
+
+    def test_reserved_names(self):
+        self.write('install.sh', b'#!/bin/sh\n', 0o755)
+        self.write('.jj/store', b'jj\n', 0o644)
+        self.commit('reserved')
+        elsewhere = os.path.join(self.tmp.name, 'out', 'install.sh')
+        installs = self.installs(self.plan('--output', elsewhere))
+        self.assertIn('install.sh', installs)
+        self.assertIn('.jj/store', installs)
+        installs = self.installs(self.plan('--output', elsewhere, '--vcs-dir', '.git', '--vcs-dir', '.jj'))
+        self.assertEqual(['.bashrc', 'bin/hello', 'install.sh'], sorted(installs))
+
+    def test_canonical_modes(self):
+        self.write('etc/conf', b'conf\n', 0o600)
+        self.write('bin/script', b'#!/bin/sh\n', 0o644)
+        self.commit('modes')
+        os.chmod(os.path.join(self.root, 'etc'), 0o700)
+        actions = self.plan('--canonical-modes')
+        self.assertIn(deftsilo.Mkdir('etc', '0755'), actions)
+        installs = self.installs(actions)
+        self.assertEqual('0644', installs['etc/conf'].mode)
+        self.assertEqual('0755', installs['bin/script'].mode)
+        self.assertEqual('0600', self.installs(self.plan())['etc/conf'].mode)
+
+    def nested_tree(self):
+        self.write('a/x', b'x\n', 0o644)
+        self.write('a/b/y', b'y\n', 0o644)
+        self.write('c/z', b'z\n', 0o644)
+        self.commit('tree')
+        return self.plan()
+
+    def test_group_by_dir(self):
+        ordered = [a.path for a in deftsilo.group_by_dir(self.nested_tree())]
+        self.assertEqual(['.bashrc', 'a', 'a/x', 'a/b', 'a/b/y', 'bin', 'bin/hello', 'c', 'c/z'], ordered)
+
+    def test_rename(self):
+        self.write('vimrc', b'vim\n', 0o644)
+        self.write('conf/a', b'a\n', 0o644)
+        self.write('conf/b', b'b\n', 0o644)
+        self.commit('rename')
+        installs = self.installs(self.plan('--rename', 'vimrc=.vim/vimrc', '--rename', 'conf/*=.config/'))
+        self.assertEqual('vimrc', installs['.vim/vimrc'].source)
+        self.assertEqual('conf/a', installs['.config/a'].source)
+        self.assertEqual('conf/b', installs['.config/b'].source)
+        # in declaration order, so a later rename sees what an earlier one made
+        installs = self.installs(self.plan('--rename', 'vimrc=vimrc.old', '--rename', 'vimrc.old=.vimrc'))
+        self.assertEqual('vimrc', installs['.vimrc'].source)
+        with self.assertRaisesRegex(deftsilo.DeftsiloError, 'install to .bashrc'):
+            self.plan('--rename', 'vimrc=.bashrc')
+
+    def test_interleave(self):
+        ordered = deftsilo.interleave(self.nested_tree())
+        seen = set()
+        for action in ordered:
+            parent = os.path.dirname(action.path)
+            self.assertTrue(not parent or parent in seen, action.path)
+            seen.add(action.path)
+        paths = [a.path for a in ordered]
+        self.assertEqual(sorted(paths), sorted(a.path for a in self.plan()))
+        # each directory comes just ahead of the first file that needs it
+        self.assertEqual(paths.index('a/b') + 1, paths.index('a/b/y'))
+        self.assertEqual(paths.index('c') + 1, paths.index('c/z'))
+
+    def test_exclude_mode(self):
+        self.write('secret', b'secret\n', 0o600)
+        self.commit('secret')
+        # each skipped file is reported on stderr, checked below
+        with contextlib.redirect_stderr(io.StringIO()):
+            self.assertEqual(['.bashrc', 'bin/hello'], sorted(self.installs(self.plan('--exclude-mode', '0600'))))
+            # with a mask, any of its bits excludes: here the executable ones
+            self.assertEqual(['.bashrc', 'secret'], sorted(self.installs(self.plan('--exclude-mode', '/0111'))))
+        self.assertIn('skipping secret: mode 0600 matches --exclude-mode 0600',
+                      self.generate('--exclude-mode', '0600').stderr)
+
+    def test_exclude_dir(self):
+        self.write('.cache/x', b'x\n', 0o644)
+        self.write('a/.cache/y', b'y\n', 0o644)
+        self.write('b/.cache', b'a file\n', 0o644)
+        self.commit('caches')
+        actions = self.plan('--exclude-dir', '.cache')
+        self.assertEqual(['.bashrc', 'b/.cache', 'bin/hello'], sorted(self.installs(actions)))
+        self.assertEqual(['a', 'b', 'bin'], sorted(a.path for a in actions if isinstance(a, deftsilo.Mkdir)))
+
+    def test_ignore_case(self):
+        self.write('README.md', b'readme\n', 0o644)
+        self.commit('readme')
+        self.assertEqual([], sorted(self.installs(self.plan('--only', 'readme.*'))))
+        self.assertEqual(['README.md'], sorted(self.installs(self.plan('--only', 'readme.*', '--ignore-case'))))
+        # every matcher, not just --only
+        self.assertEqual(['.bashrc', 'README.md'],
+                         sorted(self.installs(self.plan('--exclude-dir', 'BIN', '--ignore-case'))))
+
+    def test_utf8_check(self):
+        self.write('café', b'clean\n', 0o644)
+        self.commit('clean')
+        actions = self.plan()
+        self.assertIn('café', self.installs(actions))
+        self.assertEqual(actions, self.plan('--no-utf8-check'))
+
+    def test_linked_worktree(self):
+        # .git is a file pointing back at the main repository
+        worktree = os.path.join(self.tmp.name, 'worktree')
+        self.git('worktree', 'add', '-q', '--detach', worktree)
+        self.assertTrue(os.path.isfile(os.path.join(worktree, '.git')))
+        installs = self.installs(self.plan(directory=worktree + '/'))
+        self.assertEqual(['.bashrc', 'bin/hello'], sorted(installs))
+        self.assertEqual(sorted([sha256(b'one\n'), sha256(b'two\n')]), installs['.bashrc'].hashes)
+
+    def test_skip_git_metadata(self):
+        self.write('.gitattributes', b'* text=auto\n', 0o644)
+        self.commit('attributes')
+        self.assertIn('.gitattributes', self.installs(self.plan()))
+        self.assertNotIn('.gitattributes', self.installs(self.plan('--skip-git-metadata')))
+
+    def test_hash_namespace(self):
+        installs = self.installs(self.plan('--hash-namespace', 'work'))
+        self.assertEqual(sorted([sha256(b'work\none\n'), sha256(b'work\ntwo\n')]), installs['.bashrc'].hashes)
+
+class StatusTest(Fixture):
+    # the exit statuses in EXIT_STATUS: 2 usage, 3 path or I/O, 4 git
+
+    def test_usage_error(self):
+        self.generate('--directory', '/nonexistent', status=2)
+
+    def test_directory_is_a_file(self):
+        result = self.generate('--directory', os.path.join(self.root, '.bashrc'), status=2)
+        self.assertIn('--directory must be a directory', result.stderr)
+
+    def test_path_error(self):
+        self.generate('--dump-history', 'nope', status=3)
+
+    def test_git_error(self):
+        self.generate('--since-tag', 'nope', status=4)
+
+    def test_version_and_help(self):
+        self.assertEqual('deftsilo {}\n'.format(deftsilo.__version__), self.generate('--version').stdout)
+        self.assertIn('usage: deftsilo', self.generate('--help').stdout)
+        self.assertFalse(os.path.exists(os.path.join(self.root, 'install.sh')))
+
+    def test_completions(self):
+        for shell in ('bash', 'zsh', 'fish'):
+            output = self.generate('--completions', shell).stdout
+            self.assertIn('directory', output, shell)
+            self.assertIn('on-conflict', output, shell)
+        self.assertFalse(os.path.exists(os.path.join(self.root, 'install.sh')))
+
+    def test_world_writable(self):
+        self.write('shared', b'shared\n', 0o666)
+        self.write('open/file', b'file\n', 0o644)
+        self.commit('shared')
+        os.chmod(os.path.join(self.root, 'open'), 0o777)
+        stderr = self.generate().stderr
+        self.assertIn('shared will be installed world-writable (0666)', stderr)
+        self.assertIn('open will be installed world-writable (0777)', stderr)
+        result = self.generate('--strict-perms', status=1)
+        self.assertIn('would be installed world-writable', result.stderr)
+
+    def test_plain_directory(self):
+        plain = os.path.join(self.tmp.name, 'plain')
+        os.mkdir(plain)
+        with open(os.path.join(plain, '.bashrc'), 'w') as fout:
+            fout.write('plain\n')
+        result = subprocess.run((sys.executable, DEFTSILO, '--directory', plain + '/',
+                                 '--output', os.path.join(plain, 'install.sh')),
+                                stdout=subprocess.PIPE, stderr=subprocess.PIPE, universal_newlines=True)
+        self.assertEqual(0, result.returncode, result.stderr)
+
+class ReportTest(Fixture):
+    # the modes that print what installing into --target would do, then exit
+
+    def test_mode_diff(self):
+        os.mkdir(os.path.join(self.target, 'bin'))
+        for path, mode in (('.bashrc', 0o600), ('bin/hello', 0o755)):
+            with open(os.path.join(self.target, path), 'wb') as fout:
+                fout.write(b'two\n')
+            os.chmod(os.path.join(self.target, path), mode)
+        result = self.generate('--mode-diff', '--target', self.target)
+        self.assertEqual('.bashrc: 0600 -> 0644\n', result.stdout)
+
+class RenderTest(Fixture):
+    # the modes that print what would be installed instead of a script
+
+    def test_tree(self):
+        self.write('a/b/y', b'y\n', 0o600)
+        self.commit('tree')
+        self.assertEqual('.\n'
+                         '├── .bashrc (0644)\n'
+                         '├── a/ (0755)\n'
+                         '│   └── b/ (0755)\n'
+                         '│       └── y (0600)\n'
+                         '└── bin/ (0755)\n'
+                         '    └── hello (0755)\n', self.generate('--tree').stdout)
+        self.assertFalse(os.path.exists(os.path.join(self.root, 'install.sh')))
+
+    def test_mode_report(self):
+        self.write('a', b'a\n', 0o600)
+        self.write('b', b'b\n', 0o644)
+        self.commit('modes')
+        self.assertEqual('0644 2\n'
+                         '    .bashrc\n'
+                         '    b\n'
+                         '0755 2\n'
+                         '    bin/\n'
+                         '    bin/hello\n'
+                         '0600 1\n'
+                         '    a\n', self.generate('--mode-report').stdout)
+
+    def test_explain(self):
+        self.write('shared', b'shared\n', 0o666)
+        self.commit('shared')
+        result = self.generate('--explain')
+        sections = result.stdout.split('\n\n')
+        self.assertEqual('found 5 paths: kept 4, skipped 1', sections[0])
+        self.assertEqual('skipped:\n    .git: vcs-dir', sections[1])
+        self.assertEqual('directories:\n    bin/ 0755', sections[2])
+        self.assertIn('    .bashrc 0644, 2 hashes', sections[3].splitlines())
+        self.assertIn('    shared will be installed world-writable (0666)', sections[4].splitlines())
+        self.assertEqual('', result.stderr)
+        self.assertFalse(os.path.exists(os.path.join(self.root, 'install.sh')))
+
+class ScriptTest(Fixture):
+
+    def test_wrap_keeps_metacharacters_quoted(self):
+        self.write('&&', b'and\n', 0o644)
+        self.commit('and')
+        self.generate('--verify-source', '--wrap', '60', '--validate')
+        self.install()
+        self.assertEqual(b'and\n', self.installed('&&'))
+
+    def test_lf_endings(self):
+        for argv in ((), ('--format', 'makefile')):
+            self.generate(*argv)
+            with open(os.path.join(self.root, 'install.sh'), 'rb') as fin:
+                self.assertNotIn(b'\r', fin.read(), argv)
+
+    def test_after_orders_every_os_variant(self):
+        # zzz sorts last, so only the --after edges can put it first
+        self.write('foo.linux', b'linux\n', 0o644)
+        self.write('foo.darwin', b'darwin\n', 0o644)
+        self.write('zzz', b'dep\n', 0o644)
+        self.commit('variants')
+        self.generate('--os-suffix', '--after', 'foo=zzz')
+        with open(os.path.join(self.root, 'install.sh')) as fin:
+            lines = fin.read().splitlines()
+        def position(word):
+            return min(i for i, line in enumerate(lines) if line.strip().startswith('deftsilo_install') and word in line)
+        self.assertLess(position('zzz'), position('foo.linux'))
+        self.assertLess(position('zzz'), position('foo.darwin'))
+
+    def hardlinked_pair(self):
+        # returns (primary, secondary) as the generated script chose them
+        self.write('a', b'same\n', 0o644)
+        self.write('b', b'same\n', 0o644)
+        self.commit('pair')
+        self.generate('--hardlink-identical')
+        with open(os.path.join(self.root, 'install.sh')) as fin:
+            line = [l for l in fin.read().splitlines() if l.startswith('deftsilo_hardlink ')][0]
+        primary, secondary = line.split()[1:3]
+        return primary, secondary
+
+    def test_hardlink_identical(self):
+        primary, secondary = self.hardlinked_pair()
+        self.install()
+        self.assertEqual(b'same\n', self.installed(secondary))
+        self.assertTrue(os.path.samefile(os.path.join(self.target, primary), os.path.join(self.target, secondary)))
+
+    def test_hardlink_copies_when_the_primary_is_not_installed(self):
+        primary, secondary = self.hardlinked_pair()
+        for argv, status in ((('-c', 'skip'), 0), (('-k',), 1), (('-j', '2', '-c', 'skip'), 0)):
+            for path in (primary, secondary):
+                if os.path.exists(os.path.join(self.target, path)):
+                    os.remove(os.path.join(self.target, path))
+            with open(os.path.join(self.target, primary), 'wb') as fout:
+                fout.write(b'user edit\n')
+            self.install(*argv, status=status)
+            self.assertEqual(b'user edit\n', self.installed(primary), argv)
+            self.assertEqual(b'same\n', self.installed(secondary), argv)
+
+    def rollback(self):
+        result = subprocess.run((sys.executable, DEFTSILO, '--rollback-from',
+                                 os.path.join(self.target, '.deftsilo-checksums')),
+                                stdout=subprocess.PIPE, stderr=subprocess.PIPE, universal_newlines=True, check=True)
+        script = os.path.join(self.tmp.name, 'rollback.sh')
+        with open(script, 'w') as fout:
+            fout.write(result.stdout)
+        subprocess.run(('sh', script, self.target), check=True, stdout=subprocess.DEVNULL)
+
+    def test_rollback_removes_only_what_the_install_created(self):
+        # .bashrc was there before, with no backup to put back, so it is not the install's to remove
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'one\n')
+        self.generate('--checksum-file')
+        self.install()
+        self.rollback()
+        self.assertEqual(b'two\n', self.installed('.bashrc'))
+        self.assertFalse(os.path.exists(os.path.join(self.target, 'bin/hello')))
+        self.assertFalse(os.path.exists(os.path.join(self.target, '.deftsilo-checksums')))
+
+    def test_rollback_restores_backups(self):
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'mine\n')
+        self.generate('--checksum-file')
+        self.install('-c', 'backup')
+        self.assertEqual(b'two\n', self.installed('.bashrc'))
+        self.rollback()
+        self.assertEqual(b'mine\n', self.installed('.bashrc'))
+
+    def test_rollback_leaves_appended_files(self):
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'system\n')
+        self.generate('--checksum-file', '--append', '.bashrc')
+        self.install()
+        self.rollback()
+        self.assertTrue(self.installed('.bashrc').startswith(b'system\n'))
+
+    def test_ignore_trailing_newline(self):
+        # a target edited only in its trailing newline is a conflict unless the flag is given
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'one\n\n')
+        self.generate()
+        self.install(status=1)
+        self.assertEqual(b'one\n\n', self.installed('.bashrc'))
+        self.generate('--ignore-trailing-newline')
+        self.install()
+        self.assertEqual(b'two\n', self.installed('.bashrc'))
+
+    def test_notes_are_comments_above_their_install(self):
+        self.write('.deftsilo.notes', b'.bashrc  shell setup\n', 0o644)
+        self.commit('notes')
+        self.generate()
+        lines = self.script()
+        at = lines.index('# shell setup')
+        self.assertIn('.bashrc', lines[at + 1])
+        self.assertEqual(1, len([l for l in lines if l.startswith('# shell')]))
+
+    def test_os_suffix_installs_only_this_os(self):
+        self.write('foo.linux', b'linux\n', 0o644)
+        self.write('foo.darwin', b'darwin\n', 0o644)
+        self.commit('variants')
+        self.generate('--os-suffix')
+        self.install(env=dict(os.environ, PATH=self.fake_uname('Darwin')))
+        self.assertEqual(b'darwin\n', self.installed('foo'))
+        os.remove(os.path.join(self.target, 'foo'))
+        self.install(env=dict(os.environ, PATH=self.fake_uname('Linux')))
+        self.assertEqual(b'linux\n', self.installed('foo'))
+        os.remove(os.path.join(self.target, 'foo'))
+        self.install(env=dict(os.environ, PATH=self.fake_uname('Plan9')))
+        self.assertFalse(os.path.exists(os.path.join(self.target, 'foo')))
+
+    def test_tagged_hashes_match_under_their_algorithm(self):
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'one\n')
+        self.generate('--hash', 'blake2b')
+        self.install()
+        self.assertEqual(b'two\n', self.installed('.bashrc'))
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'edited\n')
+        self.install(status=1)
+        self.assertEqual(b'edited\n', self.installed('.bashrc'))
+
+    def test_keep_going_reports_every_conflict(self):
+        self.write('zzz', b'last\n', 0o644)
+        self.commit('last')
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'edited\n')
+        os.makedirs(os.path.join(self.target, 'bin/hello'))
+        self.generate()
+        output = self.install('-k', status=1)
+        errors = output.split('some paths were not installed:\n')[1].splitlines()
+        self.assertEqual(2, len(errors), output)
+        self.assertIn('.bashrc', errors[0])
+        self.assertIn('bin/hello', errors[1])
+        self.assertEqual(b'edited\n', self.installed('.bashrc'))
+        self.assertEqual(b'last\n', self.installed('zzz'))
+
+    def test_respect_local_mode(self):
+        dest = os.path.join(self.target, '.bashrc')
+        for argv, mode in ((('--respect-local-mode',), 0o600), ((), 0o644)):
+            with open(dest, 'wb') as fout:
+                fout.write(b'one\n')
+            os.chmod(dest, 0o600)
+            self.generate(*argv)
+            self.install()
+            self.assertEqual(b'two\n', self.installed('.bashrc'))
+            self.assertEqual(mode, os.stat(dest).st_mode & 0o777, argv)
+
+    def conflict(self):
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'edited\n')
+
+    def test_on_conflict_policies(self):
+        for policy, status, content in (('abort', 1, b'edited\n'), ('skip', 0, b'edited\n'),
+                                        ('overwrite', 0, b'two\n'), ('backup', 0, b'two\n')):
+            self.conflict()
+            self.generate('--on-conflict', policy)
+            self.install(status=status)
+            self.assertEqual(content, self.installed('.bashrc'), policy)
+        self.assertEqual(b'edited\n', self.installed('.bashrc.deftsilo.bak'))
+
+    def test_install_flag_overrides_on_conflict(self):
+        self.conflict()
+        self.generate('--on-conflict', 'overwrite')
+        self.install('-c', 'skip')
+        self.assertEqual(b'edited\n', self.installed('.bashrc'))
+
+    def test_failed_run_restores_backups(self):
+        # .bashrc is backed up and replaced before bin/hello fails the run
+        self.conflict()
+        os.makedirs(os.path.join(self.target, 'bin/hello'))
+        self.generate('--on-conflict', 'backup')
+        self.install(status=1)
+        self.assertEqual(b'edited\n', self.installed('.bashrc'))
+        self.assertFalse(os.path.exists(os.path.join(self.target, '.bashrc.deftsilo.bak')))
+
+    def test_parent_mode(self):
+        # .vim is only implied by the rename; bin is in the repository with its own mode
+        self.write('vimrc', b'vim\n', 0o644)
+        self.commit('vimrc')
+        self.generate('--rename', 'vimrc=.vim/vimrc', '--parent-mode', '0700')
+        self.install()
+        self.assertEqual(0o700, os.stat(os.path.join(self.target, '.vim')).st_mode & 0o777)
+        self.assertEqual(0o755, os.stat(os.path.join(self.target, 'bin')).st_mode & 0o777)
+        self.assertEqual(b'vim\n', self.installed('.vim/vimrc'))
+
+    def test_target_suffix(self):
+        self.generate('--target-suffix', '.new')
+        self.install()
+        self.assertEqual([], os.listdir(self.target))
+        with open(os.path.join(self.target + '.new', '.bashrc'), 'rb') as fin:
+            self.assertEqual(b'two\n', fin.read())
+
+    def test_once(self):
+        self.generate('--once', '.bashrc')
+        self.install()
+        self.assertEqual(b'two\n', self.installed('.bashrc'))
+        self.conflict()
+        self.write('.bashrc', b'three\n', 0o644)
+        self.commit('third')
+        self.generate('--once', '.bashrc')
+        self.install()
+        self.assertEqual(b'edited\n', self.installed('.bashrc'))
+
+    def test_many_hashes_read_from_a_heredoc(self):
+        for i in range(deftsilo.HASHES_INLINE_MAX + 1):
+            self.write('.bashrc', 'version {}\n'.format(i).encode(), 0o644)
+            self.commit('version {}'.format(i))
+        self.generate()
+        self.assertIn('DEFTSILO_HASHES', self.script())
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'version 0\n')
+        self.install()
+        self.assertEqual('version {}\n'.format(deftsilo.HASHES_INLINE_MAX).encode(), self.installed('.bashrc'))
+        self.conflict()
+        self.install(status=1)
+
+    def test_verify_source(self):
+        self.generate('--verify-source')
+        self.write('.bashrc', b'tampered\n', 0o644)
+        self.assertIn('refusing to install .bashrc: changed since install.sh was generated', self.install(status=1))
+        self.assertFalse(os.path.exists(os.path.join(self.target, '.bashrc')))
+
+    def test_minimal_chmod(self):
+        log = os.path.join(self.tmp.name, 'chmod.log')
+        env = dict(os.environ, PATH=self.fake_bin('chmod', 'echo "$@" >> {}\nexec {} "$@"\n'.format(
+            shlex.quote(log), shutil.which('chmod'))))
+        for argv, chmods in (((), True), (('--minimal-chmod',), False)):
+            self.generate(*argv)
+            self.install()
+            if os.path.exists(log):
+                os.remove(log)
+            self.install(env=env)
+            self.assertEqual(chmods, os.path.exists(log), argv)
+
+    def test_relative_errors(self):
+        os.makedirs(os.path.join(self.target, 'bin/hello'))
+        self.generate()
+        self.assertIn('cannot copy {}/bin/hello: would clobber a directory'.format(self.target),
+                      self.install(status=1))
+        self.generate('--relative-errors')
+        output = self.install(status=1)
+        self.assertIn('cannot copy bin/hello: would clobber a directory', output)
+        self.assertNotIn(self.target, output)
+
+    def test_record_timestamps(self):
+        self.generate('--record-timestamps')
+        self.install()
+        entries = dict(line.split(' ', 1) for line in self.installed('.deftsilo-install-log').decode().splitlines())
+        self.assertEqual(['.bashrc', 'bin/hello'], sorted(entries))
+        self.assertRegex(entries['.bashrc'], r'^\d{4}-\d\d-\d\dT\d\d:\d\d:\d\dZ$')
+        # the log is best-effort: failing to write it does not fail the install
+        os.remove(os.path.join(self.target, '.deftsilo-install-log'))
+        os.mkdir(os.path.join(self.target, '.deftsilo-install-log'))
+        os.remove(os.path.join(self.target, '.bashrc'))
+        self.install()
+        self.assertEqual(b'two\n', self.installed('.bashrc'))
+
+    def test_append(self):
+        self.generate('--append', '.bashrc')
+        self.install()
+        self.assertEqual(b'# BEGIN deftsilo managed block\ntwo\n# END deftsilo managed block\n',
+                         self.installed('.bashrc'))
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'system\n# BEGIN deftsilo managed block\ntwo\n# END deftsilo managed block\nafter\n')
+        self.write('.bashrc', b'three\n', 0o644)
+        self.commit('third')
+        self.generate('--append', '.bashrc')
+        self.install()
+        self.assertEqual(b'system\n# BEGIN deftsilo managed block\nthree\n# END deftsilo managed block\nafter\n',
+                         self.installed('.bashrc'))
+        # the hash guard covers the block alone
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'system\n# BEGIN deftsilo managed block\nedited\n# END deftsilo managed block\n')
+        self.install(status=1)
+
+    def test_custom_markers(self):
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'system\n')
+        self.generate('--append', '.bashrc', '--begin-marker', '// BEGIN', '--end-marker', '// END')
+        self.install()
+        self.assertEqual(b'system\n// BEGIN\ntwo\n// END\n', self.installed('.bashrc'))
+        self.generate('--append', '.bashrc', '--begin-marker', '', status=2)
+        self.generate('--append', '.bashrc', '--begin-marker', '// X', '--end-marker', '// X', status=2)
+
+    def test_nested_target_is_refused(self):
+        nested = os.path.join(self.root, 'nested')
+        os.mkdir(nested)
+        self.generate('--target', nested, '--conflicts', status=2)
+        self.generate()
+        self.target = nested
+        self.assertIn('it is inside', self.install(status=1))
+        self.assertEqual([], os.listdir(nested))
+        self.generate('--allow-nested')
+        self.install()
+        self.assertEqual(b'two\n', self.installed('.bashrc'))
+
+    def test_without_realpath(self):
+        # the repository reached through a symlink, so the root has to be resolved
+        link = os.path.join(self.tmp.name, 'link')
+        os.symlink(self.root, link)
+        os.mkdir(os.path.join(self.root, 'nested'))
+        self.generate()
+        env = dict(os.environ, PATH=self.path_without('realpath'))
+        for script in (os.path.join(self.root, 'install.sh'), os.path.join(link, 'install.sh')):
+            result = subprocess.run(('sh', script, os.path.join(link, 'nested')), stdout=subprocess.PIPE,
+                                    stderr=subprocess.STDOUT, universal_newlines=True, env=env)
+            self.assertEqual(1, result.returncode, result.stdout)
+            self.assertIn('it is inside', result.stdout)
+            self.install(env=env)
+            self.assertEqual(b'two\n', self.installed('.bashrc'))
+
+    def snapshot(self):
+        # every path under the target with its mode and, for files, contents
+        tree = {}
+        for dirpath, dirnames, filenames in os.walk(self.target):
+            for name in dirnames:
+                path = os.path.join(dirpath, name)
+                tree[os.path.relpath(path, self.target)] = (os.stat(path).st_mode, None)
+            for name in filenames:
+                path = os.path.join(dirpath, name)
+                with open(path, 'rb') as fin:
+                    tree[os.path.relpath(path, self.target)] = (os.stat(path).st_mode, fin.read())
+        return tree
+
+    def test_parallel_install_matches_serial(self):
+        for i in range(8):
+            self.write('dir{}/file{}'.format(i % 3, i), 'file {}\n'.format(i).encode(), 0o640 + i % 2 * 0o100)
+        self.commit('many')
+        self.generate()
+        self.install()
+        serial = self.snapshot()
+        self.target = os.path.join(self.tmp.name, 'parallel')
+        os.mkdir(self.target)
+        self.install('-j', '4')
+        self.assertEqual(serial, self.snapshot())
+        # a conflict in one job still fails the run
+        self.conflict()
+        self.install('-j', '4', status=1)
+        self.assertEqual(b'edited\n', self.installed('.bashrc'))
+
+    def test_missing_source(self):
+        self.generate()
+        os.remove(os.path.join(self.root, 'bin/hello'))
+        for argv in ((), ('-l',)):
+            output = self.install(*argv, status=1)
+            self.assertIn('missing source for bin/hello: {}/bin/hello'.format(self.root), output)
+            self.assertNotIn('cannot stat', output)
+
+    def test_collect_errors(self):
+        # a dangling symlink cannot be read, even by root
+        os.symlink('nowhere', os.path.join(self.root, 'broken'))
+        self.assertIn('broken', self.generate(status=3).stderr)
+        self.assertFalse(os.path.exists(os.path.join(self.root, 'install.sh')))
+        result = self.generate('--collect-errors', status=3)
+        self.assertIn('left out: failed to process {}: '.format(os.path.join(self.root, 'broken')), result.stderr)
+        self.install()
+        self.assertEqual(b'two\n', self.installed('.bashrc'))
+        self.assertEqual(['.bashrc', 'bin'], sorted(os.listdir(self.target)))
+
+    def test_map(self):
+        system = os.path.join(self.tmp.name, 'system')
+        os.mkdir(system)
+        self.write('etc.conf', b'system\n', 0o644)
+        self.write('vimrc', b'vim\n', 0o644)
+        self.write('.deftsilo.map', 'etc.conf  {}/etc.conf\nvimrc  .vimrc\n'.format(system).encode(), 0o644)
+        self.commit('map')
+        self.generate()
+        self.install()
+        with open(os.path.join(system, 'etc.conf'), 'rb') as fin:
+            self.assertEqual(b'system\n', fin.read())
+        self.assertEqual(b'vim\n', self.installed('.vimrc'))
+        self.assertEqual(['.bashrc', '.vimrc', 'bin'], sorted(os.listdir(self.target)))
+        self.write('.deftsilo.map', b'vimrc  .bashrc\n', 0o644)
+        self.commit('collide')
+        self.assertIn('.bashrc, vimrc all install to .bashrc', self.generate(status=1).stderr)
+
+    def test_machine_status(self):
+        self.write('tmpl', b'template\n', 0o644)
+        self.write('zzz', b'last\n', 0o644)
+        self.commit('mixed')
+        self.conflict()
+        os.mkdir(os.path.join(self.target, 'zzz'))
+        with open(os.path.join(self.target, 'tmpl'), 'wb') as fout:
+            fout.write(b'mine\n')
+        self.generate('--status-format', 'machine', '--once', 'tmpl')
+        lines = self.install('-k', status=1).splitlines()
+        statuses = {}
+        for line in lines:
+            if line.startswith('status='):
+                fields = dict(field.split('=', 1) for field in line.split())
+                statuses[fields['path']] = fields['status']
+        self.assertEqual({'.bashrc': 'CONFLICT', 'bin/hello': 'OK', 'tmpl': 'SKIPPED', 'zzz': 'ERROR'}, statuses)
+        self.assertEqual('summary ok=1 skipped=1 conflict=1 error=1', lines[-1])
+
+    def test_hash_namespace_matches_in_the_script(self):
+        with open(os.path.join(self.target, '.bashrc'), 'wb') as fout:
+            fout.write(b'one\n')
+        self.generate('--hash-namespace', 'work')
+        self.install()
+        self.assertEqual(b'two\n', self.installed('.bashrc'))
+
+    def test_mtime_from_git(self):
+        # 2020-01-02 03:04:05 UTC
+        self.write('.bashrc', b'three\n', 0o644)
+        self.git('add', '-A')
+        self.git('commit', '-q', '-m', 'dated', env=dict(os.environ, GIT_COMMITTER_DATE='1577934245 +0000'))
+        self.write('untracked', b'new\n', 0o644)
+        self.generate('--mtime-from-git')
+        lines = self.script()
+        self.assertTrue(any(line.startswith('deftsilo_mtime 202001020304.05 deftsilo_install .bashrc ')
+                            for line in lines))
+        # an untracked file keeps the time it is installed
+        self.assertTrue(any(line.startswith('deftsilo_install untracked ') for line in lines))
+        self.install()
+        self.assertEqual(1577934245, os.stat(os.path.join(self.target, '.bashrc')).st_mtime)
+
+if __name__ == '__main__':
+    unittest.main()